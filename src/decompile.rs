@@ -0,0 +1,413 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use quick_xml::events::Event;
+use reqwest::blocking::Client;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+
+/// A piece of a buffered `Item` subtree as read off the wire: either an XML
+/// event that passes through untouched, or a reference to a nested script
+/// `Item` (a script can itself parent another script) whose own finalized
+/// events get spliced back in once it comes back from the worker pool.
+pub enum Chunk {
+    Passthrough(Event<'static>),
+    Script(usize),
+}
+
+/// A single script pulled out of the place file, along with the buffered
+/// `Item` subtree (as a sequence of `Chunk`s, so a nested script `Item` can
+/// be spliced back in once it resolves) so the `Source` node can be
+/// rewritten in place once a decompilation result (if any) comes back.
+pub struct ScriptJob {
+    pub name: String,
+    pub source: String,
+    pub bytecode: Option<String>,
+    pub events: Vec<Chunk>,
+    pub source_text_idx: Option<usize>,
+    pub result: Option<String>,
+}
+
+impl ScriptJob {
+    pub fn new(
+        name: String,
+        source: String,
+        bytecode: Option<String>,
+        events: Vec<Chunk>,
+        source_text_idx: Option<usize>,
+    ) -> Self {
+        ScriptJob {
+            name,
+            source,
+            bytecode,
+            events,
+            source_text_idx,
+            result: None,
+        }
+    }
+
+    /// The `Source` text to splice back into the place file: the original
+    /// watermark (its first six lines, same as the Oracle-decompiled body
+    /// carries) followed by the decompiled body, or the untouched original
+    /// source if there was no bytecode or the decompile failed.
+    pub fn finalize_source(&self) -> String {
+        match &self.result {
+            Some(decompiled) => {
+                let watermark = self.source.lines().take(6).collect::<Vec<_>>().join("\n");
+                format!("{}\n{}", watermark, decompiled)
+            }
+            None => self.source.clone(),
+        }
+    }
+}
+
+enum Outcome {
+    NoBytecode,
+    CacheHit { text: String },
+    Decompiled { text: String, elapsed_ms: u128 },
+    Failed(String),
+}
+
+/// A content-addressed on-disk cache keyed by the SHA-1 of a script's
+/// base64 bytecode, so identical bytecode (repeated modules within a run,
+/// or a re-run over a barely-edited place file) is only ever decompiled
+/// once. A `None` directory means the cache is disabled and every lookup
+/// and store is a no-op.
+pub struct Cache {
+    dir: Option<PathBuf>,
+}
+
+impl Cache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        if let Some(dir) = &dir {
+            let _ = fs::create_dir_all(dir);
+        }
+        Cache { dir }
+    }
+
+    fn path_for(&self, bytecode: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let mut hasher = Sha1::new();
+        hasher.update(bytecode.as_bytes());
+        Some(dir.join(format!("{:x}.lua", hasher.finalize())))
+    }
+
+    fn get(&self, bytecode: &str) -> Option<String> {
+        fs::read_to_string(self.path_for(bytecode)?).ok()
+    }
+
+    fn put(&self, bytecode: &str, text: &str) {
+        if let Some(path) = self.path_for(bytecode) {
+            let _ = fs::write(path, text);
+        }
+    }
+}
+
+/// The outcome of a single HTTP attempt, before the retry loop decides what
+/// to do with it.
+enum Attempt {
+    Decompiled { text: String, elapsed_ms: u128 },
+    Fatal(String),
+    Retryable { message: String, retry_after: Option<Duration> },
+}
+
+/// Parses a `Retry-After` header value, which per RFC 9110 is either a
+/// number of seconds or an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(value).ok()?;
+    when.duration_since(SystemTime::now()).ok()
+}
+
+/// `base * 2^attempt`, capped and jittered by a few hundred milliseconds so
+/// a thundering herd of workers doesn't retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let base = Duration::from_millis(500);
+    let capped = base.saturating_mul(1 << attempt.min(6)).min(Duration::from_secs(30));
+    let jitter_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_millis() % 250;
+    capped + Duration::from_millis(jitter_ms as u64)
+}
+
+/// A bare-bones token bucket: each `acquire` call blocks until the next
+/// slot, spaced `1 / rate` seconds apart, becomes available. Shared across
+/// workers so the Oracle endpoint sees a single capped request rate.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        let interval = if rate > 0.0 {
+            Duration::from_secs_f64(1.0 / rate)
+        } else {
+            Duration::from_secs(0)
+        };
+        RateLimiter {
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn acquire(&self) {
+        let wait_until = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        let now = Instant::now();
+        if wait_until > now {
+            thread::sleep(wait_until - now);
+        }
+    }
+}
+
+fn send(client: &Client, base_url: &str, key: &str, bytecode: &str) -> Attempt {
+    let start = SystemTime::now();
+    match client
+        .post(base_url)
+        .header("Authorization", format!("Bearer {}", key))
+        .body(
+            serde_json::to_string(&json!({
+                "script": bytecode
+            }))
+            .unwrap(),
+        )
+        .send()
+    {
+        Ok(dec) => {
+            let retry_after = dec
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+
+            match dec.status() {
+                StatusCode::OK => match dec.text() {
+                    Ok(text) => {
+                        let elapsed = start.elapsed().expect("Time went backwards").as_millis();
+                        Attempt::Decompiled { text, elapsed_ms: elapsed }
+                    }
+                    Err(e) => Attempt::Fatal(format!("bad response body: {e}")),
+                },
+                StatusCode::TOO_MANY_REQUESTS => Attempt::Retryable {
+                    message: dec.text().ok().unwrap_or_else(|| "unlucky".into()),
+                    retry_after,
+                },
+                StatusCode::SERVICE_UNAVAILABLE => Attempt::Retryable {
+                    message: "Service unavailable".into(),
+                    retry_after,
+                },
+                StatusCode::INTERNAL_SERVER_ERROR => Attempt::Retryable {
+                    message: "Internal server error".into(),
+                    retry_after,
+                },
+                StatusCode::PAYMENT_REQUIRED | StatusCode::UNAUTHORIZED => {
+                    Attempt::Fatal(dec.text().ok().unwrap_or_else(|| "unlucky".into()))
+                }
+                StatusCode::BAD_REQUEST => Attempt::Fatal("Update the app please please please please".into()),
+                code => Attempt::Fatal(format!("something went wrong: {code}")),
+            }
+        }
+        Err(e) => Attempt::Fatal(format!("error: {e:?}")),
+    }
+}
+
+/// Retries `send` on `429`/`503`/`500` up to `max_retries` times, honoring
+/// a `Retry-After` header when present and otherwise backing off
+/// exponentially. Every other status fails fast.
+fn decompile_one(
+    client: &Client,
+    limiter: &RateLimiter,
+    base_url: &str,
+    key: &str,
+    job: &ScriptJob,
+    max_retries: u32,
+    cache: &Cache,
+) -> Outcome {
+    let bytecode = match &job.bytecode {
+        Some(bytecode) => bytecode,
+        None => return Outcome::NoBytecode,
+    };
+
+    if let Some(text) = cache.get(bytecode) {
+        return Outcome::CacheHit { text };
+    }
+
+    let mut attempt = 0;
+    loop {
+        limiter.acquire();
+        match send(client, base_url, key, bytecode) {
+            Attempt::Decompiled { text, elapsed_ms } => {
+                cache.put(bytecode, &text);
+                return Outcome::Decompiled { text, elapsed_ms };
+            }
+            Attempt::Fatal(message) => return Outcome::Failed(message),
+            Attempt::Retryable { message, retry_after } => {
+                if attempt >= max_retries {
+                    return Outcome::Failed(format!("{} (gave up after {} retries)", message, attempt));
+                }
+                thread::sleep(retry_after.unwrap_or_else(|| backoff(attempt)));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Dispatches every collected `ScriptJob` across `concurrency` worker
+/// threads, each with its own `reqwest` client, sharing a single rate
+/// limiter capped at `rate` requests/sec. Jobs come back over a channel and
+/// are reassembled in their original order so the caller can splice each
+/// job's (possibly unchanged) events back into the document.
+pub fn run(
+    jobs: Vec<ScriptJob>,
+    base_url: &str,
+    key: &str,
+    concurrency: usize,
+    rate: f64,
+    max_retries: u32,
+    cache: Cache,
+) -> Vec<ScriptJob> {
+    let total = jobs.len() as u64;
+    let queue = Arc::new(Mutex::new(VecDeque::from_iter(jobs.into_iter().enumerate())));
+    let limiter = Arc::new(RateLimiter::new(rate));
+    let progress = Arc::new(Mutex::new(0u64));
+    let cache = Arc::new(cache);
+    let (tx, rx) = mpsc::channel();
+
+    let handles: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let limiter = Arc::clone(&limiter);
+            let progress = Arc::clone(&progress);
+            let cache = Arc::clone(&cache);
+            let tx = tx.clone();
+            let base_url = base_url.to_string();
+            let key = key.to_string();
+
+            thread::spawn(move || {
+                let client = Client::new();
+                loop {
+                    let (idx, mut job) = match queue.lock().unwrap().pop_front() {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+
+                    let outcome_text = match decompile_one(&client, &limiter, &base_url, &key, &job, max_retries, &cache) {
+                        Outcome::NoBytecode => "no bytecode!".to_string(),
+                        Outcome::CacheHit { text } => {
+                            job.result = Some(text);
+                            "cached!".to_string()
+                        }
+                        Outcome::Decompiled { text, elapsed_ms } => {
+                            job.result = Some(text);
+                            format!("decompiled in {}ms!", elapsed_ms)
+                        }
+                        Outcome::Failed(msg) => msg,
+                    };
+
+                    {
+                        let mut count = progress.lock().unwrap();
+                        *count += 1;
+                        println!("[{}/{}] Decompiling {}... {}", *count, total, job.name, outcome_text);
+                        let _ = io::stdout().flush();
+                    }
+
+                    tx.send((idx, job)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results: Vec<Option<ScriptJob>> = (0..total as usize).map(|_| None).collect();
+    for (idx, job) in rx {
+        results[idx] = Some(job);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    results.into_iter().map(|job| job.expect("every queued job reports back")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn parse_retry_after_accepts_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parse_retry_after_accepts_http_date() {
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let header = httpdate::fmt_http_date(future);
+        let parsed = parse_retry_after(&header).expect("valid HTTP-date should parse");
+        // Formatting truncates to whole seconds, so allow a little slack.
+        assert!(parsed.as_secs() > 3500 && parsed.as_secs() <= 3600);
+    }
+
+    #[test]
+    fn parse_retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after("not a date"), None);
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        assert!(backoff(0) >= Duration::from_millis(500));
+        assert!(backoff(0) < Duration::from_secs(1));
+        assert!(backoff(3) >= Duration::from_secs(4));
+        assert!(backoff(10) >= Duration::from_secs(30));
+        assert!(backoff(10) <= Duration::from_secs(31));
+    }
+
+    fn temp_cache_dir(label: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "oracle-postprocess-test-{}-{}-{:?}",
+            label,
+            std::process::id(),
+            thread::current().id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn cache_put_then_get_round_trips() {
+        let dir = temp_cache_dir("roundtrip");
+        let cache = Cache::new(Some(dir.clone()));
+        cache.put("some-bytecode", "-- decompiled body");
+        assert_eq!(cache.get("some-bytecode"), Some("-- decompiled body".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_get_misses_unknown_bytecode() {
+        let dir = temp_cache_dir("miss");
+        let cache = Cache::new(Some(dir.clone()));
+        assert_eq!(cache.get("never-stored"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_cache_is_always_a_miss() {
+        let cache = Cache::new(None);
+        cache.put("some-bytecode", "-- decompiled body");
+        assert_eq!(cache.get("some-bytecode"), None);
+    }
+}
@@ -1,14 +1,18 @@
+mod decompile;
+mod rbxl;
+
 use clap::Parser;
+use decompile::{Cache, Chunk, ScriptJob};
 use regex::Regex;
-use reqwest::blocking::Client;
-use reqwest::StatusCode;
-use serde_json::json;
+use rbxl::Format;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, Cursor, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use std::{env, fs, process};
-use quick_xml::events::Event;
-use quick_xml::Reader;
+use quick_xml::events::{BytesEnd, BytesText, Event};
+use quick_xml::{Reader, Writer};
 
 /// A rbxlx postprocessor that decompiles everything inside 😋
 #[derive(Parser, Debug)]
@@ -31,136 +35,268 @@ struct Args {
     /// Oracle decompiler url
     #[arg(long, default_value = "https://oracle.mshq.dev/decompile")]
     base_url: String,
+
+    /// Number of scripts to decompile concurrently
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Maximum decompilation requests per second across all workers
+    #[arg(long, default_value_t = 10.0)]
+    rate: f64,
+
+    /// Maximum retries for a 429/503/500 before giving up on a script
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    /// Directory for the content-addressed decompilation cache
+    /// Defaults to the OS cache dir (e.g. ~/.cache/oracle-postprocess)
+    #[arg(long, verbatim_doc_comment)]
+    cache_dir: Option<PathBuf>,
+
+    /// Disable the on-disk decompilation cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Force the input container format instead of auto-detecting it
+    #[arg(long, value_enum, default_value_t = FormatArg::Auto)]
+    format: FormatArg,
+
+    /// Output container format
+    /// Defaults to matching the (possibly forced) input format
+    /// Only the input format is accepted; any other value exits with an error
+    #[arg(long, value_enum, verbatim_doc_comment, default_value_t = FormatArg::Auto)]
+    output_format: FormatArg,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+enum FormatArg {
+    Auto,
+    Xml,
+    Binary,
 }
 
 fn main() {
     let args = Args::parse();
 
     let env_key = env::var("ORACLE_KEY").ok();
-    let arg_key = args.key;
+    let arg_key = args.key.clone();
 
     let key = arg_key.or(env_key).unwrap_or_else(|| {
         eprintln!("Oracle key not provided");
         process::exit(1);
     });
 
-    let mut reader = Reader::from_file(&args.input_file).unwrap_or_else(|e| {
+    let input_bytes = fs::read(&args.input_file).unwrap_or_else(|e| {
         eprintln!("Can't read the file: {}", e);
         process::exit(1);
     });
 
-    let mut buf = Vec::new();
-    let mut output = Vec::new();
-    let mut in_script = false;
-    let mut script_name = String::new();
-    let mut script_source = String::new();
-    let mut total = 0u64;
-    let mut decompiled = 0u64;
+    let extension = Path::new(&args.input_file).extension().and_then(|e| e.to_str());
+    let input_format = match args.format {
+        FormatArg::Auto => rbxl::format_for(extension, &input_bytes),
+        FormatArg::Xml => Format::Xml,
+        FormatArg::Binary => Format::Binary,
+    };
+    let output_format = match args.output_format {
+        FormatArg::Auto => input_format,
+        FormatArg::Xml => Format::Xml,
+        FormatArg::Binary => Format::Binary,
+    };
+    if output_format != input_format {
+        eprintln!(
+            "Converting between formats isn't supported yet (input is {:?}, output is {:?})",
+            input_format, output_format
+        );
+        process::exit(1);
+    }
+
+    let cache_dir = (!args.no_cache).then(|| {
+        args.cache_dir.clone().unwrap_or_else(|| dirs::cache_dir().unwrap_or_else(env::temp_dir).join("oracle-postprocess"))
+    });
 
     let start = SystemTime::now();
 
+    let output = match input_format {
+        Format::Binary => {
+            let cache = Cache::new(cache_dir);
+            rbxl::run(&input_bytes, &args.base_url, &key, args.concurrency, args.rate, args.max_retries, cache).unwrap_or_else(|e| {
+                eprintln!("Can't parse the place file: {}", e);
+                process::exit(1);
+            })
+        }
+        Format::Xml => run_xml(&input_bytes, &args, &key, Cache::new(cache_dir)),
+    };
+
+    let elapsed = start.elapsed().expect("Time went backwards");
+    println!("Processed in {}s!", elapsed.as_secs());
+
+    print!("Writing output to {}... ", args.output);
+    let _ = io::stdout().flush();
+
+    let mut file = File::create(&args.output).unwrap();
+    file.write_all(&output).unwrap();
+    println!("Done!");
+}
+
+/// The state of a script `Item` subtree currently being buffered. Scripts
+/// can parent other scripts (e.g. a `LocalScript` under a `Script`), so
+/// these nest: starting a nested script `Item` pushes the outer one here and
+/// resumes it once the nested job is finalized.
+struct ScriptBuilder {
+    name: String,
+    source: String,
+    source_text_idx: Option<usize>,
+    buf: Vec<Chunk>,
+    item_depth: u32,
+}
+
+impl ScriptBuilder {
+    fn new(item_event: Event<'static>) -> Self {
+        ScriptBuilder {
+            name: String::new(),
+            source: String::new(),
+            source_text_idx: None,
+            buf: vec![Chunk::Passthrough(item_event)],
+            item_depth: 1,
+        }
+    }
+}
+
+fn is_script_item(e: &quick_xml::events::BytesStart, reader: &Reader<Cursor<&[u8]>>) -> bool {
+    e.attributes()
+        .find(|attr| attr.as_ref().unwrap().key == b"class")
+        .map(|class| class.unwrap().unescape_and_decode_value(reader).unwrap())
+        .is_some_and(|class| class == "ModuleScript" || class == "LocalScript" || class == "Script")
+}
+
+fn run_xml(input_bytes: &[u8], args: &Args, key: &str, cache: Cache) -> Vec<u8> {
+    let mut reader = Reader::from_reader(Cursor::new(input_bytes));
+
+    let mut buf = Vec::new();
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut jobs: Vec<ScriptJob> = Vec::new();
+
+    // The script `Item` currently being scanned, if any, plus the stack of
+    // outer scripts it's nested inside (see `ScriptBuilder`).
+    let mut current: Option<ScriptBuilder> = None;
+    let mut stack: Vec<ScriptBuilder> = Vec::new();
+
+    let re = Regex::new(r"-- Bytecode \(Base64\):\n-- (.*)\n\n").unwrap();
+
     loop {
-        match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) if e.name() == b"Item" => {
-                if let Some(class) = e.attributes().find(|attr| attr.as_ref().unwrap().key == b"class") {
-                    let class = class.unwrap().unescape_and_decode_value(&reader).unwrap();
-                    if class == "ModuleScript" || class == "LocalScript" || class == "Script" {
-                        in_script = true;
-                        total += 1;
+        let event = match reader.read_event(&mut buf) {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Error parsing XML: {}", e);
+                process::exit(1);
+            }
+        };
+
+        match event {
+            Event::Start(ref e) if e.name() == b"Item" => {
+                let is_script = is_script_item(e, &reader);
+                match &mut current {
+                    Some(cur) if is_script => {
+                        stack.push(mem::replace(cur, ScriptBuilder::new(event.into_owned())));
                     }
+                    Some(cur) => {
+                        cur.item_depth += 1;
+                        cur.buf.push(Chunk::Passthrough(event.into_owned()));
+                    }
+                    None if is_script => current = Some(ScriptBuilder::new(event.into_owned())),
+                    None => chunks.push(Chunk::Passthrough(event.into_owned())),
                 }
             }
-            Ok(Event::Start(ref e)) if in_script && e.name() == b"Properties" => {
-                script_name.clear();
-                script_source.clear();
+            Event::Start(ref e) if matches!(&current, Some(cur) if cur.item_depth == 1) && e.name() == b"Properties" => {
+                let cur = current.as_mut().unwrap();
+                cur.name.clear();
+                cur.source.clear();
+                cur.buf.push(Chunk::Passthrough(event.into_owned()));
             }
-            Ok(Event::Start(ref e)) if in_script && e.name() == b"string" => {
-                if let Some(name) = e.attributes().find(|attr| attr.as_ref().unwrap().key == b"name") {
-                    let name = name.unwrap().unescape_and_decode_value(&reader).unwrap();
-                    if name == "Name" {
-                        script_name = reader.read_text(e.name(), &mut Vec::new()).unwrap();
-                    } else if name == "Source" {
-                        script_source = reader.read_text(e.name(), &mut Vec::new()).unwrap();
+            Event::Start(ref e) if matches!(&current, Some(cur) if cur.item_depth == 1) && e.name() == b"string" => {
+                let cur = current.as_mut().unwrap();
+                let tag_name = e.name().to_vec();
+                let attr_name = e
+                    .attributes()
+                    .find(|attr| attr.as_ref().unwrap().key == b"name")
+                    .map(|attr| attr.unwrap().unescape_and_decode_value(&reader).unwrap());
+
+                cur.buf.push(Chunk::Passthrough(event.into_owned()));
+
+                match attr_name.as_deref() {
+                    Some("Name") => {
+                        cur.name = reader.read_text(&tag_name, &mut Vec::new()).unwrap();
+                        cur.buf.push(Chunk::Passthrough(Event::Text(BytesText::from_plain_str(&cur.name).into_owned())));
+                    }
+                    Some("Source") => {
+                        cur.source = reader.read_text(&tag_name, &mut Vec::new()).unwrap();
+                        cur.source_text_idx = Some(cur.buf.len());
+                        cur.buf.push(Chunk::Passthrough(Event::Text(BytesText::from_plain_str(&cur.source).into_owned())));
+                    }
+                    _ => {
+                        let text = reader.read_text(&tag_name, &mut Vec::new()).unwrap();
+                        if !text.is_empty() {
+                            cur.buf.push(Chunk::Passthrough(Event::Text(BytesText::from_plain_str(&text).into_owned())));
+                        }
                     }
                 }
+                cur.buf.push(Chunk::Passthrough(Event::End(BytesEnd::owned(tag_name))));
             }
-            Ok(Event::End(ref e)) if in_script && e.name() == b"Item" => {
-                in_script = false;
-                decompiled += 1;
-                print!(
-                    "[{}/{}] Decompiling {}... ",
-                    decompiled,
-                    total,
-                    script_name
-                );
-                let _ = io::stdout().flush();
-
-                let re = Regex::new(r"-- Bytecode \(Base64\):\n-- (.*)\n\n").unwrap();
-                let b64_bytecode = re
-                    .captures(&script_source)
-                    .and_then(|it| it.get(1).map(|it| it.as_str()));
-
-                let watermark = script_source.lines().take(6).collect::<Vec<_>>().join("\n");
-
-                if let Some(bytecode) = b64_bytecode {
-                    let start = SystemTime::now();
-                    match Client::new()
-                        .post(&args.base_url)
-                        .header("Authorization", format!("Bearer {}", key))
-                        .body(
-                            serde_json::to_string(&json!({
-                                "script": bytecode
-                            }))
-                            .unwrap(),
-                        )
-                        .send()
-                    {
-                        Ok(dec) => {
-                            match dec.status() {
-                                StatusCode::OK => {
-                                    if let Ok(deserialized) = dec.text() {
-                                        script_source = format!("{}\n{}", watermark, deserialized);
-                                    }
-                                    let elapsed = start.elapsed()
-                                        .expect("Time went backwards");
-                                    println!("decompiled in {}ms!", elapsed.as_millis());
-                                }
-                                StatusCode::PAYMENT_REQUIRED
-                                | StatusCode::TOO_MANY_REQUESTS
-                                | StatusCode::UNAUTHORIZED => {
-                                    println!("{}", dec.text().ok().unwrap_or("unlucky".into()))
-                                }
-                                StatusCode::INTERNAL_SERVER_ERROR => {
-                                    println!("Internal server error")
-                                }
-                                StatusCode::BAD_REQUEST => {
-                                    println!("Update the app please please please please")
-                                }
-                                code => println!("something went wrong: {code}"),
-                            }
-                        }
-                        Err(e) => {
-                            println!("error: {e:?}");
-                        }
+            Event::End(ref e) if current.is_some() && e.name() == b"Item" => {
+                let mut cur = current.take().unwrap();
+                cur.buf.push(Chunk::Passthrough(event.into_owned()));
+                cur.item_depth -= 1;
+                if cur.item_depth == 0 {
+                    let bytecode = re.captures(&cur.source).and_then(|it| it.get(1).map(|it| it.as_str().to_string()));
+
+                    jobs.push(ScriptJob::new(cur.name, cur.source, bytecode, cur.buf, cur.source_text_idx));
+                    let job_idx = jobs.len() - 1;
+
+                    current = stack.pop();
+                    match &mut current {
+                        Some(outer) => outer.buf.push(Chunk::Script(job_idx)),
+                        None => chunks.push(Chunk::Script(job_idx)),
                     }
                 } else {
-                    println!("no bytecode!");
+                    current = Some(cur);
                 }
             }
-            Ok(Event::Eof) => break,
-            _ => (),
+            _ => match &mut current {
+                Some(cur) => cur.buf.push(Chunk::Passthrough(event.into_owned())),
+                None => chunks.push(Chunk::Passthrough(event.into_owned())),
+            },
         }
         buf.clear();
     }
 
-    let elapsed = start.elapsed()
-        .expect("Time went backwards");
-    println!("Processed in {}s!", elapsed.as_secs());
+    println!("Found {} script(s), dispatching to {} worker(s)...", jobs.len(), args.concurrency.max(1));
+    let jobs = decompile::run(jobs, &args.base_url, key, args.concurrency, args.rate, args.max_retries, cache);
 
-    print!("Writing output to {}... ", args.output);
-    let _ = io::stdout().flush();
+    let mut writer = Writer::new(Vec::new());
+    for chunk in &chunks {
+        write_chunk(&mut writer, chunk, &jobs);
+    }
 
-    let mut file = File::create(args.output).unwrap();
-    file.write_all(&output).unwrap();
-    println!("Done!");
+    writer.into_inner()
+}
+
+/// Writes a single `Chunk` to `writer`, recursing into a script `Item`'s own
+/// buffered `Chunk`s (which may themselves contain further nested scripts)
+/// and rewriting its `Source` text node in place.
+fn write_chunk(writer: &mut Writer<Vec<u8>>, chunk: &Chunk, jobs: &[ScriptJob]) {
+    match chunk {
+        Chunk::Passthrough(event) => writer.write_event(event).unwrap(),
+        Chunk::Script(idx) => {
+            let job = &jobs[*idx];
+            for (i, inner) in job.events.iter().enumerate() {
+                if job.source_text_idx == Some(i) {
+                    let final_source = job.finalize_source();
+                    writer.write_event(Event::Text(BytesText::from_plain_str(&final_source).into_owned())).unwrap();
+                } else {
+                    write_chunk(writer, inner, jobs);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use lz4_flex::block::{compress as lz4_compress, decompress as lz4_decompress};
+use regex::Regex;
+
+use crate::decompile::{self, Cache, ScriptJob};
+
+const BINARY_MAGIC: &[u8] = b"<roblox!";
+const XML_MAGIC: &[u8] = b"<roblox ";
+const XML_DECL: &[u8] = b"<?xml";
+const FILE_HEADER_LEN: usize = 32;
+
+/// The two place-file container formats this tool understands.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Xml,
+    Binary,
+}
+
+/// Sniffs the container format from the leading magic bytes, the same way
+/// Roblox Studio itself tells `.rbxlx` and `.rbxl` apart.
+pub fn sniff(bytes: &[u8]) -> Option<Format> {
+    if bytes.starts_with(BINARY_MAGIC) {
+        Some(Format::Binary)
+    } else if bytes.starts_with(XML_MAGIC) || bytes.starts_with(XML_DECL) {
+        Some(Format::Xml)
+    } else {
+        None
+    }
+}
+
+/// Falls back to the file extension when the magic bytes are inconclusive
+/// (e.g. an empty or truncated file).
+pub fn format_for(extension: Option<&str>, bytes: &[u8]) -> Format {
+    sniff(bytes).unwrap_or(match extension {
+        Some("rbxl") => Format::Binary,
+        _ => Format::Xml,
+    })
+}
+
+/// One `<name><compressedLen><uncompressedLen><reserved>` chunk as laid out
+/// in a binary place file, with its body already split out.
+struct ChunkHeader {
+    name: [u8; 4],
+    compressed_len: u32,
+    uncompressed_len: u32,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|slice| u32::from_le_bytes(slice.try_into().unwrap()))
+        .ok_or_else(|| format!("truncated file: expected 4 bytes at offset {offset}"))
+}
+
+fn read_chunk(bytes: &[u8], offset: usize) -> Result<(ChunkHeader, &[u8], usize), String> {
+    if offset + 16 > bytes.len() {
+        return Err(format!("truncated file: expected a 16-byte chunk header at offset {offset}"));
+    }
+    let mut name = [0u8; 4];
+    name.copy_from_slice(&bytes[offset..offset + 4]);
+    let compressed_len = read_u32(bytes, offset + 4)?;
+    let uncompressed_len = read_u32(bytes, offset + 8)?;
+    // offset + 12..16 is a reserved, always-zero u32.
+    let body_start = offset + 16;
+    let stored_len = if compressed_len == 0 { uncompressed_len } else { compressed_len } as usize;
+    let body = bytes
+        .get(body_start..body_start + stored_len)
+        .ok_or_else(|| format!("truncated file: chunk at offset {offset} claims {stored_len} bytes of body"))?;
+    let header = ChunkHeader { name, compressed_len, uncompressed_len };
+    Ok((header, body, body_start + stored_len))
+}
+
+fn decompress_body(header: &ChunkHeader, body: &[u8]) -> Result<Vec<u8>, String> {
+    if header.compressed_len == 0 {
+        Ok(body.to_vec())
+    } else {
+        lz4_decompress(body, header.uncompressed_len as usize).map_err(|e| format!("malformed LZ4 chunk body: {e}"))
+    }
+}
+
+fn read_binary_string(bytes: &[u8], offset: usize) -> Result<(String, usize), String> {
+    let len = read_u32(bytes, offset)? as usize;
+    let start = offset + 4;
+    let slice = bytes
+        .get(start..start + len)
+        .ok_or_else(|| format!("truncated file: string at offset {offset} claims {len} bytes"))?;
+    Ok((String::from_utf8_lossy(slice).into_owned(), start + len))
+}
+
+/// A decoded `PROP` chunk of type `String` for the `Source` property of a
+/// script class, pending patched-in decompilation results.
+struct SourcePropChunk {
+    class_id: u32,
+    entries: Vec<String>,
+}
+
+/// A slot in the rebuilt file: either a chunk copied through byte-for-byte,
+/// or a reference to a `SourcePropChunk` that needs re-serializing once the
+/// decompilation results are in.
+enum Slot {
+    Verbatim(Vec<u8>),
+    SourceProp(usize),
+}
+
+fn build_chunk(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let compressed = lz4_compress(body);
+    let mut chunk = Vec::with_capacity(16 + compressed.len());
+    chunk.extend_from_slice(name);
+    chunk.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(&0u32.to_le_bytes());
+    chunk.extend_from_slice(&compressed);
+    chunk
+}
+
+/// Runs the decompilation pass over a binary `.rbxl` buffer.
+///
+/// Unlike the XML path, this does not fully decode the instance tree: `INST`
+/// chunks are only peeked at for the `classID -> className` mapping,
+/// and every chunk other than a `String`-typed `PROP` chunk for `Source` on
+/// a script class is copied through unchanged. That's enough to find every
+/// script's bytecode and splice the decompiled body back in, without having
+/// to understand Roblox's full property/referent encoding.
+pub fn run(
+    bytes: &[u8],
+    base_url: &str,
+    key: &str,
+    concurrency: usize,
+    rate: f64,
+    max_retries: u32,
+    cache: Cache,
+) -> Result<Vec<u8>, String> {
+    if bytes.len() < FILE_HEADER_LEN {
+        return Err(format!("truncated file: expected at least a {FILE_HEADER_LEN}-byte header"));
+    }
+
+    let mut class_names: HashMap<u32, String> = HashMap::new();
+    let mut slots: Vec<Slot> = Vec::new();
+    let mut source_props: Vec<SourcePropChunk> = Vec::new();
+
+    let mut offset = FILE_HEADER_LEN;
+    while offset < bytes.len() {
+        let (header, body, next_offset) = read_chunk(bytes, offset)?;
+        let chunk_bytes = bytes[offset..next_offset].to_vec();
+
+        if &header.name == b"INST" {
+            let decoded = decompress_body(&header, body)?;
+            let class_id = read_u32(&decoded, 0)?;
+            let (class_name, _) = read_binary_string(&decoded, 4)?;
+            class_names.insert(class_id, class_name);
+            slots.push(Slot::Verbatim(chunk_bytes));
+        } else if &header.name == b"PROP" {
+            let decoded = decompress_body(&header, body)?;
+            let class_id = read_u32(&decoded, 0)?;
+            let (prop_name, after_name) = read_binary_string(&decoded, 4)?;
+            let prop_type = *decoded
+                .get(after_name)
+                .ok_or_else(|| format!("truncated file: PROP chunk at offset {offset} is missing its type byte"))?;
+            let is_script_source = prop_name == "Source"
+                && prop_type == 0x01
+                && matches!(
+                    class_names.get(&class_id).map(String::as_str),
+                    Some("Script") | Some("LocalScript") | Some("ModuleScript")
+                );
+
+            if is_script_source {
+                let mut cursor = after_name + 1;
+                let mut entries = Vec::new();
+                while cursor < decoded.len() {
+                    let (entry, next) = read_binary_string(&decoded, cursor)?;
+                    entries.push(entry);
+                    cursor = next;
+                }
+                source_props.push(SourcePropChunk { class_id, entries });
+                slots.push(Slot::SourceProp(source_props.len() - 1));
+            } else {
+                slots.push(Slot::Verbatim(chunk_bytes));
+            }
+        } else {
+            slots.push(Slot::Verbatim(chunk_bytes));
+        }
+
+        offset = next_offset;
+    }
+
+    let re = Regex::new(r"-- Bytecode \(Base64\):\n-- (.*)\n\n").unwrap();
+    let mut jobs = Vec::new();
+    let mut targets = Vec::new();
+    for (chunk_idx, chunk) in source_props.iter().enumerate() {
+        for (entry_idx, source) in chunk.entries.iter().enumerate() {
+            let bytecode = re.captures(source).and_then(|it| it.get(1).map(|it| it.as_str().to_string()));
+            jobs.push(ScriptJob::new(
+                format!("{}#{}", class_names.get(&chunk.class_id).map(String::as_str).unwrap_or("Script"), entry_idx),
+                source.clone(),
+                bytecode,
+                Vec::new(),
+                None,
+            ));
+            targets.push((chunk_idx, entry_idx));
+        }
+    }
+
+    let jobs = decompile::run(jobs, base_url, key, concurrency, rate, max_retries, cache);
+    for (job, (chunk_idx, entry_idx)) in jobs.into_iter().zip(targets) {
+        source_props[chunk_idx].entries[entry_idx] = job.finalize_source();
+    }
+
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(&bytes[..FILE_HEADER_LEN]);
+    for slot in slots {
+        match slot {
+            Slot::Verbatim(chunk) => output.extend_from_slice(&chunk),
+            Slot::SourceProp(idx) => {
+                let chunk = &source_props[idx];
+                let mut body = Vec::new();
+                body.extend_from_slice(&chunk.class_id.to_le_bytes());
+                body.extend_from_slice(&("Source".len() as u32).to_le_bytes());
+                body.extend_from_slice(b"Source");
+                body.push(0x01);
+                for entry in &chunk.entries {
+                    body.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+                    body.extend_from_slice(entry.as_bytes());
+                }
+                output.extend_from_slice(&build_chunk(b"PROP", &body));
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_detects_binary_magic() {
+        assert_eq!(sniff(b"<roblox!\x89\xff\r\n\x1a\n"), Some(Format::Binary));
+    }
+
+    #[test]
+    fn sniff_detects_xml_magic() {
+        assert_eq!(sniff(b"<roblox version=\"4\">"), Some(Format::Xml));
+    }
+
+    #[test]
+    fn sniff_detects_xml_declaration() {
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), Some(Format::Xml));
+    }
+
+    #[test]
+    fn sniff_returns_none_for_unrelated_bytes() {
+        assert_eq!(sniff(b"not a place file"), None);
+    }
+
+    #[test]
+    fn format_for_falls_back_to_extension_on_inconclusive_bytes() {
+        assert_eq!(format_for(Some("rbxl"), b""), Format::Binary);
+        assert_eq!(format_for(Some("rbxlx"), b""), Format::Xml);
+        assert_eq!(format_for(None, b""), Format::Xml);
+    }
+
+    #[test]
+    fn format_for_prefers_sniffed_format_over_extension() {
+        assert_eq!(format_for(Some("rbxlx"), b"<roblox!\x89\xff\r\n\x1a\n"), Format::Binary);
+    }
+
+    #[test]
+    fn build_chunk_round_trips_through_read_chunk() {
+        let body = b"hello binary world";
+        let chunk = build_chunk(b"TEST", body);
+
+        let mut file = Vec::new();
+        file.extend_from_slice(&[0u8; FILE_HEADER_LEN]);
+        file.extend_from_slice(&chunk);
+
+        let (header, decoded_body, next_offset) = read_chunk(&file, FILE_HEADER_LEN).unwrap();
+        assert_eq!(&header.name, b"TEST");
+        assert_eq!(next_offset, file.len());
+        let decompressed = decompress_body(&header, decoded_body).unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn read_chunk_rejects_truncated_header() {
+        let file = vec![0u8; FILE_HEADER_LEN + 4];
+        assert!(read_chunk(&file, FILE_HEADER_LEN).is_err());
+    }
+
+    #[test]
+    fn read_binary_string_round_trips() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(5u32).to_le_bytes());
+        bytes.extend_from_slice(b"hello");
+        let (text, next) = read_binary_string(&bytes, 0).unwrap();
+        assert_eq!(text, "hello");
+        assert_eq!(next, bytes.len());
+    }
+
+    #[test]
+    fn read_binary_string_rejects_truncated_payload() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(50u32).to_le_bytes());
+        bytes.extend_from_slice(b"too short");
+        assert!(read_binary_string(&bytes, 0).is_err());
+    }
+}